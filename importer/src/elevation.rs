@@ -0,0 +1,224 @@
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+
+use geom::Distance;
+use map_model::raw::RawMap;
+
+// SRTM marks a missing sample with this sentinel.
+const VOID: i16 = -32768;
+
+/// A single SRTM `.hgt` elevation tile: a square grid of big-endian signed 16-bit elevations (in
+/// meters) covering one degree of latitude/longitude, with row 0 being the northernmost line. The
+/// filename encodes the tile's southwest corner, like `N47W122.hgt`.
+pub struct SrtmTile {
+    samples: Vec<i16>,
+    // The tile is always square -- either 1201x1201 (3 arc-second) or 3601x3601 (1 arc-second).
+    size: usize,
+    sw_lat: f64,
+    sw_lon: f64,
+}
+
+impl SrtmTile {
+    pub fn load(path: &str) -> Result<SrtmTile, std::io::Error> {
+        let (sw_lat, sw_lon) = parse_sw_corner(path)?;
+
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        if bytes.len() % 2 != 0 {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{} has an odd number of bytes, not a valid .hgt file", path),
+            ));
+        }
+        let samples: Vec<i16> = bytes
+            .chunks_exact(2)
+            .map(|pair| i16::from_be_bytes([pair[0], pair[1]]))
+            .collect();
+        let size = (samples.len() as f64).sqrt().round() as usize;
+        if size * size != samples.len() {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{} isn't a square grid of samples", path),
+            ));
+        }
+
+        Ok(SrtmTile {
+            samples,
+            size,
+            sw_lat,
+            sw_lon,
+        })
+    }
+
+    fn covers(&self, lon: f64, lat: f64) -> bool {
+        let frac_lat = lat - self.sw_lat;
+        let frac_lon = lon - self.sw_lon;
+        (0.0..=1.0).contains(&frac_lat) && (0.0..=1.0).contains(&frac_lon)
+    }
+
+    fn raw(&self, row: usize, col: usize) -> Option<i16> {
+        let sample = self.samples[row * self.size + col];
+        if sample == VOID {
+            None
+        } else {
+            Some(sample)
+        }
+    }
+
+    /// Bilinearly interpolate the elevation (in meters) at a longitude/latitude known to fall
+    /// within this tile. Void (-32768) corners fall back to the nearest valid corner found.
+    fn sample(&self, lon: f64, lat: f64) -> Option<f64> {
+        if !self.covers(lon, lat) {
+            return None;
+        }
+        let n = self.size;
+        let row_f = (1.0 - (lat - self.sw_lat)) * (n - 1) as f64;
+        let col_f = (lon - self.sw_lon) * (n - 1) as f64;
+        let row0 = (row_f.floor() as usize).min(n - 1);
+        let col0 = (col_f.floor() as usize).min(n - 1);
+        let row1 = (row0 + 1).min(n - 1);
+        let col1 = (col0 + 1).min(n - 1);
+        let dr = row_f - row0 as f64;
+        let dc = col_f - col0 as f64;
+
+        let corners = [
+            self.raw(row0, col0),
+            self.raw(row0, col1),
+            self.raw(row1, col0),
+            self.raw(row1, col1),
+        ];
+        // Corners are row-major, so for corner `k` the two corners sharing its row or column are
+        // `k ^ 1` and `k ^ 2`, and the diagonal one is `3 - k`.
+        let at = |k: usize| -> Option<f64> {
+            [k, k ^ 1, k ^ 2, 3 - k]
+                .iter()
+                .find_map(|&j| corners[j])
+                .map(f64::from)
+        };
+        let (nw, ne, sw, se) = (at(0)?, at(1)?, at(2)?, at(3)?);
+
+        let top = nw * (1.0 - dc) + ne * dc;
+        let bottom = sw * (1.0 - dc) + se * dc;
+        Some(top * (1.0 - dr) + bottom * dr)
+    }
+}
+
+// Parses a southwest corner like "N47W122" (the SRTM filename convention) out of a path.
+fn parse_sw_corner(path: &str) -> Result<(f64, f64), std::io::Error> {
+    let bad = |msg: String| std::io::Error::new(ErrorKind::InvalidInput, msg);
+
+    let stem = Path::new(path)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .ok_or_else(|| bad(format!("{} has no file stem", path)))?;
+    if stem.len() < 7 {
+        return Err(bad(format!("{} doesn't look like an SRTM filename", stem)));
+    }
+
+    let lat_sign = match &stem[0..1] {
+        "N" => 1.0,
+        "S" => -1.0,
+        _ => return Err(bad(format!("{} doesn't start with N/S", stem))),
+    };
+    let lat: f64 = stem[1..3]
+        .parse()
+        .map_err(|_| bad(format!("{} has a bad latitude", stem)))?;
+
+    let lon_sign = match &stem[3..4] {
+        "E" => 1.0,
+        "W" => -1.0,
+        _ => return Err(bad(format!("{} doesn't have E/W in the right place", stem))),
+    };
+    let lon: f64 = stem[4..7]
+        .parse()
+        .map_err(|_| bad(format!("{} has a bad longitude", stem)))?;
+
+    Ok((lat_sign * lat, lon_sign * lon))
+}
+
+/// Sample `tile` at every road endpoint and intermediate center-line vertex in `map`, so that
+/// downhill/uphill routing and rendering have real elevation data instead of the all-zero
+/// default.
+pub fn apply_elevation(map: &mut RawMap, tile: &SrtmTile) {
+    let gps_bounds = map.gps_bounds.clone();
+    let mut missing = 0;
+    for road in map.roads.values_mut() {
+        let mut elevation = Vec::with_capacity(road.center_points.len());
+        for pt in &road.center_points {
+            let gps = pt.to_gps(&gps_bounds);
+            let z = tile.sample(gps.x(), gps.y()).unwrap_or_else(|| {
+                missing += 1;
+                0.0
+            });
+            elevation.push(Distance::meters(z));
+        }
+        road.elevation = elevation;
+    }
+    if missing > 0 {
+        warn!(
+            "{} road points fell outside the SRTM tile or had only void samples around them, and got elevation 0",
+            missing
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(sw_lat: f64, sw_lon: f64, size: usize, samples: Vec<i16>) -> SrtmTile {
+        SrtmTile {
+            samples,
+            size,
+            sw_lat,
+            sw_lon,
+        }
+    }
+
+    #[test]
+    fn parse_sw_corner_handles_all_four_quadrants() {
+        assert_eq!(parse_sw_corner("N47W122.hgt").unwrap(), (47.0, -122.0));
+        assert_eq!(parse_sw_corner("S01E005.hgt").unwrap(), (-1.0, 5.0));
+        assert_eq!(parse_sw_corner("N35E139.hgt").unwrap(), (35.0, 139.0));
+        assert_eq!(parse_sw_corner("S34W071.hgt").unwrap(), (-34.0, -71.0));
+    }
+
+    #[test]
+    fn parse_sw_corner_rejects_garbage() {
+        assert!(parse_sw_corner("not_an_hgt_file.hgt").is_err());
+        assert!(parse_sw_corner("short.hgt").is_err());
+    }
+
+    #[test]
+    fn covers_respects_tile_bounds() {
+        let t = tile(47.0, -122.0, 2, vec![0, 0, 0, 0]);
+        assert!(t.covers(-121.5, 47.5));
+        assert!(!t.covers(-120.9, 47.5));
+        assert!(!t.covers(-121.5, 48.1));
+    }
+
+    #[test]
+    fn sample_bilinearly_interpolates_a_2x2_grid() {
+        // row 0 (north) is [0, 10], row 1 (south) is [20, 30].
+        let t = tile(0.0, 0.0, 2, vec![0, 10, 20, 30]);
+        assert_eq!(t.sample(0.5, 0.5), Some(15.0));
+        assert_eq!(t.sample(0.0, 1.0), Some(0.0));
+        assert_eq!(t.sample(1.0, 0.0), Some(30.0));
+    }
+
+    #[test]
+    fn sample_falls_back_to_nearest_valid_corner_when_void() {
+        const VOID_I16: i16 = -32768;
+        let t = tile(0.0, 0.0, 2, vec![VOID_I16, 10, 20, 30]);
+        // The void corner (north-west) should fall back to the nearest valid sample found (10),
+        // instead of treating -32768 as a real elevation.
+        assert_eq!(t.sample(0.0, 1.0), Some(10.0));
+    }
+
+    #[test]
+    fn sample_returns_none_outside_tile() {
+        let t = tile(0.0, 0.0, 2, vec![0, 10, 20, 30]);
+        assert_eq!(t.sample(5.0, 5.0), None);
+    }
+}
@@ -25,8 +25,8 @@ pub struct GenericCityImporter {
     pub onstreet_parking: convert_osm::OnstreetParking,
     pub public_offstreet_parking: convert_osm::PublicOffstreetParking,
     pub private_offstreet_parking: convert_osm::PrivateOffstreetParking,
-    /// If provided, pull elevation data from this SRTM file. The SRTM parser is incorrect, so the
-    /// results will be nonsense.
+    /// If provided, pull elevation data from this SRTM `.hgt` file and assign it to every road's
+    /// center-line points.
     pub elevation: Option<String>,
     /// OSM railway=rail will be included as light rail if so. Cosmetic only.
     pub include_railroads: bool,
@@ -63,7 +63,7 @@ impl GenericCityImporter {
             config,
         );
 
-        let map = convert_osm::convert(
+        let mut map = convert_osm::convert(
             convert_osm::Options {
                 osm_input: abstio::path(format!("input/{}/osm/{}.osm", name.city, name.map)),
                 name: name.clone(),
@@ -78,6 +78,21 @@ impl GenericCityImporter {
             },
             timer,
         );
+        // Imported ways are only as smooth as their raw OSM node density; round off the sharpest
+        // bends before anything downstream tries to fit corners against them.
+        map_model::make::curves::smooth_sharp_bends(&mut map, timer);
+
+        // Cycleways/footways that were mapped as separate ways, but that just run alongside a
+        // main road, produce spurious degenerate intersections. Fold them back in as lanes.
+        map_model::make::sidepaths::zip_sidepaths(&mut map, timer);
+
+        if let Some(path) = &self.elevation {
+            match crate::elevation::SrtmTile::load(path) {
+                Ok(tile) => crate::elevation::apply_elevation(&mut map, &tile),
+                Err(err) => warn!("Couldn't load elevation data from {}: {}", path, err),
+            }
+        }
+
         map.save();
         map
     }
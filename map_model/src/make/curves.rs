@@ -0,0 +1,271 @@
+use dimensioned::si;
+use geom::{Angle, PolyLine, Pt2D};
+
+use crate::raw::RawMap;
+use abstutil::Timer;
+
+// A bend sharper than this between two consecutive segments of an imported way is jagged enough
+// to be worth smoothing out.
+const SHARP_BEND_DEGREES: f64 = 35.0;
+
+/// Walk every road's center line, and wherever two consecutive segments meet at a sharp angle
+/// (usually a sign that the OSM way just didn't have enough nodes to represent a gentle curve),
+/// replace that corner with a short generated curve through the neighboring points. This is what
+/// lets `make_new_polygon` fit clean corners instead of the "breaks weirdly" case caused by short,
+/// sharply-angled last segments.
+pub fn smooth_sharp_bends(map: &mut RawMap, timer: &mut Timer) {
+    let mut smoothed = 0;
+    for road in map.roads.values_mut() {
+        let pts = road.center_points.clone();
+        if pts.len() < 3 {
+            continue;
+        }
+        road.center_points = smooth_points(&pts, &mut smoothed);
+    }
+
+    if smoothed > 0 {
+        timer.note(format!(
+            "Smoothed {} sharp bends in imported road geometry",
+            smoothed
+        ));
+    }
+}
+
+// Walks `pts` once, building the output from what was actually written to it (never the
+// original array), so that smoothing one bend can't leave a later window comparing against a
+// point that's no longer there. When a bend gets replaced by a generated curve, both `cur` and
+// `next` are consumed at once -- the curve already runs from `prev` to `next` -- so the next
+// window starts fresh after `next` instead of re-examining it as if it were untouched.
+fn smooth_points(pts: &[Pt2D], smoothed: &mut usize) -> Vec<Pt2D> {
+    let mut new_pts = vec![pts[0]];
+    let mut idx = 1;
+    while idx < pts.len() - 1 {
+        let prev = *new_pts.last().unwrap();
+        let cur = pts[idx];
+        let next = pts[idx + 1];
+        let in_angle = prev.angle_to(cur);
+        let out_angle = cur.angle_to(next);
+        let diff = (in_angle.normalized_degrees() - out_angle.normalized_degrees()).abs();
+        let diff = diff.min(360.0 - diff);
+
+        if diff > SHARP_BEND_DEGREES {
+            let curve = densify_curve(prev, next, Some(in_angle), Some(out_angle));
+            new_pts.extend(curve.into_iter().skip(1));
+            *smoothed += 1;
+            idx += 2;
+        } else {
+            new_pts.push(cur);
+            idx += 1;
+        }
+    }
+    let last = *pts.last().unwrap();
+    if new_pts.last() != Some(&last) {
+        new_pts.push(last);
+    }
+    new_pts.dedup();
+    new_pts
+}
+
+// How far apart to place samples along a generated curve. Matches the rough node density you'd
+// see along a gently-curving OSM way, so downstream code (corner fitting, shifting) doesn't
+// notice the difference between an imported and a generated curve.
+const SAMPLE_SPACING: si::Meter<f64> = si::Meter {
+    value_unsafe: 5.0,
+    _marker: std::marker::PhantomData,
+};
+
+/// Generate a smooth center line between two endpoints, given an optional tangent (facing away
+/// from the point) at each end. Without hints, this just samples a straight line; with one or
+/// both hints, it fits a cubic Hermite spline so the generated points ease into the given
+/// direction instead of producing the jagged corners you get from sparse OSM nodes.
+///
+/// Used by the importer to smooth sharp bends after `convert_osm::convert`, so
+/// `initial_intersection_polygon` and `make_new_polygon` have well-behaved polylines to shift and
+/// intersect.
+pub fn densify_curve(
+    start: Pt2D,
+    end: Pt2D,
+    start_tangent: Option<Angle>,
+    end_tangent: Option<Angle>,
+) -> Vec<Pt2D> {
+    let straight_line_dist = start.dist_to(end);
+    let num_samples = ((straight_line_dist / SAMPLE_SPACING).round() as usize).max(1);
+
+    let (t0, t1) = match (start_tangent, end_tangent) {
+        (None, None) => return straight_line_points(start, end, num_samples),
+        (t0, t1) => (
+            t0.unwrap_or_else(|| start.angle_to(end)),
+            t1.unwrap_or_else(|| start.angle_to(end)),
+        ),
+    };
+
+    // Hermite basis functions, with tangent vectors scaled to the chord length -- the usual trick
+    // to keep the curve from bulging wildly when the endpoints are close together.
+    let scale = straight_line_dist;
+    let m0 = (t0.cos() * scale, t0.sin() * scale);
+    let m1 = (t1.cos() * scale, t1.sin() * scale);
+
+    let mut pts = Vec::with_capacity(num_samples + 1);
+    for step in 0..=num_samples {
+        let t = (step as f64) / (num_samples as f64);
+        let t2 = t * t;
+        let t3 = t2 * t;
+
+        let h00 = 2.0 * t3 - 3.0 * t2 + 1.0;
+        let h10 = t3 - 2.0 * t2 + t;
+        let h01 = -2.0 * t3 + 3.0 * t2;
+        let h11 = t3 - t2;
+
+        let x = h00 * start.x() + h10 * m0.0 + h01 * end.x() + h11 * m1.0;
+        let y = h00 * start.y() + h10 * m0.1 + h01 * end.y() + h11 * m1.1;
+        pts.push(Pt2D::new(x, y));
+    }
+    // Floating-point roundoff can nudge the first/last sample off the exact endpoint; pin them
+    // down so callers can rely on the curve starting and ending exactly where asked.
+    pts[0] = start;
+    *pts.last_mut().unwrap() = end;
+    pts.dedup();
+    pts
+}
+
+fn straight_line_points(start: Pt2D, end: Pt2D, num_samples: usize) -> Vec<Pt2D> {
+    let mut pts = Vec::with_capacity(num_samples + 1);
+    for step in 0..=num_samples {
+        let t = (step as f64) / (num_samples as f64);
+        pts.push(Pt2D::new(
+            start.x() + t * (end.x() - start.x()),
+            start.y() + t * (end.y() - start.y()),
+        ));
+    }
+    pts
+}
+
+/// Split a polyline at `dist` along it, returning `([start..cut], [cut..end])`. The cut point is
+/// inserted exactly once into each half, so re-concatenating the two (minus the duplicated cut
+/// point) reproduces the original polyline. Splitting at or beyond either end instead returns the
+/// whole polyline on that side and its first (or last) segment on the other.
+pub fn split(pl: &PolyLine, dist: si::Meter<f64>) -> (PolyLine, PolyLine) {
+    let dist = dist.max(0.0 * si::M).min(pl.length());
+    let cut_pt = pl.dist_along(dist).0;
+    let all_pts = pl.points();
+
+    // Splitting exactly at either endpoint leaves one side with no real length. Rather than
+    // synthesize a single-point "polyline" (which `PolyLine::new` won't accept), treat the whole
+    // original line as the non-empty side and a minimal stub as the empty one.
+    if dist <= 0.0 * si::M {
+        return (
+            PolyLine::new(vec![all_pts[0], all_pts[1]]).unwrap(),
+            pl.clone(),
+        );
+    }
+    if dist >= pl.length() {
+        return (
+            pl.clone(),
+            PolyLine::new(vec![all_pts[all_pts.len() - 2], *all_pts.last().unwrap()]).unwrap(),
+        );
+    }
+
+    let mut first_pts = Vec::new();
+    let mut second_pts = Vec::new();
+    let mut traveled = 0.0 * si::M;
+
+    for (idx, pt) in all_pts.iter().enumerate() {
+        if idx > 0 {
+            traveled += all_pts[idx - 1].dist_to(*pt);
+        }
+        if traveled < dist {
+            first_pts.push(*pt);
+        } else if traveled > dist {
+            // The cut falls strictly between the previous point and this one.
+            if first_pts.last() != Some(&cut_pt) {
+                first_pts.push(cut_pt);
+            }
+            if second_pts.is_empty() {
+                second_pts.push(cut_pt);
+            }
+            second_pts.push(*pt);
+        } else {
+            // `pt` itself sits exactly at `dist` -- it IS the cut point, so it belongs to both
+            // halves, but only once each.
+            first_pts.push(*pt);
+            second_pts.push(*pt);
+        }
+    }
+
+    (
+        PolyLine::new(first_pts).unwrap(),
+        PolyLine::new(second_pts).unwrap(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_at_exact_interior_vertex_has_no_duplicates() {
+        let pl = PolyLine::new(vec![
+            Pt2D::new(0.0, 0.0),
+            Pt2D::new(10.0, 0.0),
+            Pt2D::new(20.0, 0.0),
+            Pt2D::new(30.0, 0.0),
+        ])
+        .unwrap();
+        // Cutting exactly at the second vertex (10m in) is the case `densify_curve`'s own evenly-
+        // spaced samples routinely produce.
+        let (first, second) = split(&pl, 10.0 * si::M);
+        assert_eq!(first.points(), &vec![Pt2D::new(0.0, 0.0), Pt2D::new(10.0, 0.0)]);
+        assert_eq!(
+            second.points(),
+            &vec![
+                Pt2D::new(10.0, 0.0),
+                Pt2D::new(20.0, 0.0),
+                Pt2D::new(30.0, 0.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn split_at_interior_non_vertex_distance() {
+        let pl = PolyLine::new(vec![Pt2D::new(0.0, 0.0), Pt2D::new(30.0, 0.0)]).unwrap();
+        let (first, second) = split(&pl, 10.0 * si::M);
+        assert_eq!(first.length(), 10.0 * si::M);
+        assert_eq!(second.length(), 20.0 * si::M);
+    }
+
+    #[test]
+    fn smooth_points_handles_two_consecutive_sharp_bends() {
+        // A zig-zag where every bend is sharp; the bug this regresses against produced a
+        // duplicate point (and PolyLine::new panics on those) as soon as a smoothed bend was
+        // followed by another window.
+        let pts = vec![
+            Pt2D::new(0.0, 0.0),
+            Pt2D::new(10.0, 10.0),
+            Pt2D::new(20.0, 0.0),
+            Pt2D::new(30.0, 10.0),
+            Pt2D::new(40.0, 0.0),
+        ];
+        let mut smoothed = 0;
+        let result = smooth_points(&pts, &mut smoothed);
+        assert!(smoothed > 0);
+        // No two consecutive points should be identical.
+        for pair in result.windows(2) {
+            assert_ne!(pair[0], pair[1]);
+        }
+        assert_eq!(*result.first().unwrap(), pts[0]);
+        assert_eq!(*result.last().unwrap(), *pts.last().unwrap());
+    }
+
+    #[test]
+    fn smooth_points_leaves_gentle_bends_alone() {
+        let pts = vec![
+            Pt2D::new(0.0, 0.0),
+            Pt2D::new(10.0, 0.1),
+            Pt2D::new(20.0, 0.0),
+        ];
+        let mut smoothed = 0;
+        let result = smooth_points(&pts, &mut smoothed);
+        assert_eq!(smoothed, 0);
+        assert_eq!(result, pts);
+    }
+}
@@ -1,8 +1,9 @@
-use crate::{Intersection, IntersectionID, Road, RoadID, LANE_THICKNESS};
+use crate::{Intersection, IntersectionID, LaneType, Road, RoadID, LANE_THICKNESS};
 use abstutil::note;
 use abstutil::wraparound_get;
 use dimensioned::si;
-use geom::{Angle, Line, PolyLine, Pt2D};
+use geom::{Angle, Line, PolyLine, Pt2D, Ring};
+use std::collections::HashMap;
 use std::marker;
 
 const DEGENERATE_INTERSECTION_HALF_LENGTH: si::Meter<f64> = si::Meter {
@@ -10,8 +11,39 @@ const DEGENERATE_INTERSECTION_HALF_LENGTH: si::Meter<f64> = si::Meter {
     _marker: marker::PhantomData,
 };
 
+/// Run the dual-carriageway/short-connector merge pass over the whole road/intersection list once,
+/// then compute every intersection's polygon. This is the actual entry point into this file --
+/// calling `initial_intersection_polygon` directly on unmerged geometry is exactly the case its
+/// angle-sort "definitely can break" on.
+pub fn make_all_intersection_polygons(
+    roads: &mut Vec<Road>,
+    intersections: &mut Vec<Intersection>,
+) -> HashMap<IntersectionID, Vec<Pt2D>> {
+    crate::make::merge_intersections::merge_dual_carriageways_and_short_connectors(
+        roads,
+        intersections,
+    );
+
+    let mut polygons = HashMap::new();
+    for idx in 0..intersections.len() {
+        if intersections[idx].roads.is_empty() {
+            // Emptied out by the merge pass above (its roads got fused into some other
+            // intersection); nothing left here to compute a polygon for.
+            continue;
+        }
+        let poly = initial_intersection_polygon(&intersections[idx], roads);
+        polygons.insert(intersections[idx].id, poly);
+    }
+    polygons
+}
+
 // The polygon should exist entirely within the thick bands around all original roads -- it just
 // carves up part of that space, doesn't reach past it.
+//
+// Callers should run `merge_intersections::merge_dual_carriageways_and_short_connectors` over the
+// whole road/intersection list first -- it gets rid of two common sources of the pathological
+// many-road, merged-geometry intersections that the angle-sort below still can't handle well.
+// `make_all_intersection_polygons` above is the entry point that does this.
 pub fn initial_intersection_polygon(i: &Intersection, roads: &mut Vec<Road>) -> Vec<Pt2D> {
     // Turn all of the incident roads into two PolyLines (the "forwards" and "backwards" borders of
     // the road, if the roads were oriented to both be incoming to the intersection), both ending
@@ -213,6 +245,118 @@ pub fn initial_intersection_polygon(i: &Intersection, roads: &mut Vec<Road>) ->
     endpoints
 }
 
+// Where a road's center line actually touches each of its endpoints. For merged intersections,
+// the incident roads don't all necessarily meet at one literal point, so this is keyed by
+// `IntersectionID` rather than just assuming "last point" means the same thing for every road.
+struct RoadEndpoints {
+    at: HashMap<IntersectionID, Pt2D>,
+}
+
+impl RoadEndpoints {
+    fn new(r: &Road) -> RoadEndpoints {
+        let mut at = HashMap::new();
+        at.insert(r.src_i, r.center_pts.first_pt());
+        at.insert(r.dst_i, r.center_pts.last_pt());
+        RoadEndpoints { at }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum CommonEndpoint {
+    // Both roads' center lines touch `i` at the same point.
+    Same,
+    // The roads both touch `i`, but not at the same point (a merged intersection).
+    Different,
+}
+
+fn common_endpoint(r1: &Road, r2: &Road, i: IntersectionID) -> CommonEndpoint {
+    let pt1 = RoadEndpoints::new(r1).at[&i];
+    let pt2 = RoadEndpoints::new(r2).at[&i];
+    classify_common_endpoint(pt1, pt2)
+}
+
+// The actual comparison `common_endpoint` makes, pulled out so it can be tested without
+// constructing full `Road` fixtures: do the two roads' endpoints at this intersection literally
+// coincide, or did the intersection get merged such that they don't?
+fn classify_common_endpoint(pt1: Pt2D, pt2: Pt2D) -> CommonEndpoint {
+    if pt1 == pt2 {
+        CommonEndpoint::Same
+    } else {
+        CommonEndpoint::Different
+    }
+}
+
+#[cfg(test)]
+mod common_endpoint_tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn classify_common_endpoint_same_point() {
+        let pt = Pt2D::new(5.0, 10.0);
+        assert_eq!(classify_common_endpoint(pt, pt), CommonEndpoint::Same);
+    }
+
+    #[test]
+    fn classify_common_endpoint_different_points() {
+        assert_eq!(
+            classify_common_endpoint(Pt2D::new(5.0, 10.0), Pt2D::new(5.0, 10.1)),
+            CommonEndpoint::Different
+        );
+    }
+
+    // Only fills in the fields `common_endpoint` actually reads.
+    fn test_road(id: usize, src: usize, dst: usize, center: PolyLine) -> Road {
+        Road {
+            id: RoadID(id),
+            src_i: IntersectionID(src),
+            dst_i: IntersectionID(dst),
+            center_pts: center,
+            children_forwards: Vec::new(),
+            children_backwards: Vec::new(),
+            osm_tags: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn common_endpoint_reads_the_per_road_endpoint_at_this_intersection_not_just_any_endpoint() {
+        // `common_endpoint` has to look up *this* intersection's endpoint on each road via
+        // `RoadEndpoints`, not just compare `first_pt`/`last_pt` directly -- a road might end at
+        // `i` via either its `src_i` or its `dst_i` depending on which way it happens to be
+        // digitized. Build one road of each orientation, both truly ending at the same point, and
+        // confirm that's still recognized as `Same`.
+        let i = IntersectionID(0);
+        let r1 = test_road(
+            1,
+            0,
+            10,
+            PolyLine::new(vec![Pt2D::new(0.0, 0.0), Pt2D::new(100.0, 0.0)]).unwrap(),
+        );
+        // r2 ends at `i` via `dst_i` instead of `src_i`, and is digitized the opposite direction.
+        let r2 = test_road(
+            2,
+            11,
+            0,
+            PolyLine::new(vec![Pt2D::new(0.0, -100.0), Pt2D::new(0.0, 0.0)]).unwrap(),
+        );
+        assert_eq!(common_endpoint(&r1, &r2, i), CommonEndpoint::Same);
+
+        // Nudge r2's endpoint at `i` a hair away -- the kind of tiny divergence a merged
+        // intersection's roads actually have -- and it should flip to `Different`.
+        let r2_merged = test_road(
+            2,
+            11,
+            0,
+            PolyLine::new(vec![Pt2D::new(0.0, -100.0), Pt2D::new(0.5, 0.0)]).unwrap(),
+        );
+        assert_eq!(
+            common_endpoint(&r1, &r2_merged, i),
+            CommonEndpoint::Different
+        );
+    }
+}
+
 fn make_new_polygon(
     roads: &mut Vec<Road>,
     i: IntersectionID,
@@ -222,8 +366,8 @@ fn make_new_polygon(
     // Find the two corners of each road
     for idx in 0..lines.len() as isize {
         let (id, _, fwd_pl, back_pl) = wraparound_get(&lines, idx);
-        let (_back_id, _, adj_back_pl, _) = wraparound_get(&lines, idx + 1);
-        let (_fwd_id, _, _, adj_fwd_pl) = wraparound_get(&lines, idx - 1);
+        let (back_id, _, adj_back_pl, _) = wraparound_get(&lines, idx + 1);
+        let (fwd_id, _, _, adj_fwd_pl) = wraparound_get(&lines, idx - 1);
 
         // road_center ends at the intersection.
         // TODO This is redoing some work. :\
@@ -237,7 +381,7 @@ fn make_new_polygon(
         // three-way intersection (or maybe just a case where the angles of the two adjacent roads
         // are super close). In that case, we only have one corner to choose as a candidate for
         // trimming back the road center.
-        let (fwd_hit, new_center1) = {
+        let (mut fwd_hit, mut new_center1) = {
             if let Some((hit, angle)) = fwd_pl.intersection(adj_fwd_pl) {
                 // Find where the perpendicular to this corner hits the original line
                 let perp = Line::new(hit, hit.project_away(1.0, angle.rotate_degs(90.0)));
@@ -249,7 +393,7 @@ fn make_new_polygon(
                 (None, None)
             }
         };
-        let (back_hit, new_center2) = {
+        let (mut back_hit, mut new_center2) = {
             if let Some((hit, angle)) = back_pl.intersection(adj_back_pl) {
                 // Find where the perpendicular to this corner hits the original line
                 let perp = Line::new(hit, hit.project_away(1.0, angle.rotate_degs(90.0)));
@@ -262,6 +406,61 @@ fn make_new_polygon(
             }
         };
 
+        if new_center1.is_none() && new_center2.is_none() {
+            // Neither the forward nor backward edge hits its neighbor directly. This happens a
+            // lot at merged intersections, where the incident roads don't all meet at one literal
+            // point. Fall back to CommonEndpoint reasoning: if exactly one side actually shares
+            // its endpoint with the neighboring road (at this intersection), we can still find a
+            // sensible corner by dropping a perpendicular from the *other* (non-shared) edge's
+            // last point and intersecting it with the neighbor's non-shared polyline -- just like
+            // the direct-intersection case above, so it still feeds into the same trimming logic
+            // below instead of bypassing it.
+            let fwd_common = common_endpoint(&roads[id.0], &roads[fwd_id.0], i);
+            let back_common = common_endpoint(&roads[id.0], &roads[back_id.0], i);
+
+            if fwd_common == back_common {
+                // Either both sides already share an endpoint (nothing to project), or neither
+                // does (too ambiguous to guess a corner) -- give up on the whole polygon like
+                // before.
+                note(format!(
+                    "{} adjacent to {} fwd, {} back, but CommonEndpoint is {:?} for both",
+                    id, fwd_id, back_id, fwd_common
+                ));
+                return None;
+            }
+
+            if fwd_common == CommonEndpoint::Same {
+                // The forward edge already meets its neighbor at the shared corner, so it needs
+                // no trimming; `new_center1` stays `None` and the backward side alone decides
+                // `shorter_center` below. Project the backward edge's corner instead.
+                let perp = Line::new(
+                    back_pl.last_pt(),
+                    back_pl
+                        .last_pt()
+                        .project_away(1.0, back_pl.last_line().angle().rotate_degs(90.0)),
+                );
+                let hit = adj_back_pl.intersection_infinite_line(perp)?;
+                let trim_to = road_center.intersection_infinite_line(perp)?;
+                let mut c = road_center.clone();
+                c.trim_to_pt(trim_to);
+                back_hit = Some(hit);
+                new_center2 = Some(c);
+            } else {
+                let perp = Line::new(
+                    fwd_pl.last_pt(),
+                    fwd_pl
+                        .last_pt()
+                        .project_away(1.0, fwd_pl.last_line().angle().rotate_degs(90.0)),
+                );
+                let hit = adj_fwd_pl.intersection_infinite_line(perp)?;
+                let trim_to = road_center.intersection_infinite_line(perp)?;
+                let mut c = road_center.clone();
+                c.trim_to_pt(trim_to);
+                fwd_hit = Some(hit);
+                new_center1 = Some(c);
+            }
+        }
+
         let shorter_center = match (new_center1, new_center2) {
             (Some(c1), Some(c2)) => {
                 if c1.length() <= c2.length() {
@@ -272,42 +471,7 @@ fn make_new_polygon(
             }
             (Some(c1), None) => c1,
             (None, Some(c2)) => c2,
-            (None, None) => {
-                // TODO This doesn't work yet, and it's getting VERY complicated.
-                /*
-                // Different strategy. Take the perpendicular infinite line and intersect with the
-                // adjacent line that does NOT share an endpoint.
-                let fwd_same_endpt = fwd_pl.last_pt() == adj_fwd_pl.last_pt();
-                let back_same_endpt = back_pl.last_pt() == adj_back_pl.last_pt();
-
-                let debug = i.0 == 357;
-                if debug {
-                    note(format!(
-                        "{} adjacent to {} fwd, {} back. same endpts: {} and {}",
-                        id, fwd_id, back_id, fwd_same_endpt, back_same_endpt
-                    ));
-                }
-
-                if (fwd_same_endpt || back_same_endpt) && !(fwd_same_endpt && back_same_endpt) {
-                    if fwd_same_endpt {
-                        let perp = Line::new(back_pl.last_pt(), back_pl.last_pt().project_away(1.0, back_pl.last_line().angle().rotate_degs(90.0)));
-                        let adj_hit = adj_back_pl.intersection_infinite_line(perp)?;
-                        endpoints.push(fwd_pl.last_pt());
-                        endpoints.push(adj_hit);
-                    } else {
-                        let perp = Line::new(fwd_pl.last_pt(), fwd_pl.last_pt().project_away(1.0, fwd_pl.last_line().angle().rotate_degs(90.0)));
-                        let adj_hit = adj_fwd_pl.intersection_infinite_line(perp)?;
-                        endpoints.push(adj_hit);
-                        endpoints.push(back_pl.last_pt());
-                    }
-                    continue;
-                } else {
-                    // TODO whoa, how's this happen?
-                    return None;
-                }
-                */
-                return None;
-            }
+            (None, None) => unreachable!(),
         };
 
         // TODO This is redoing LOTS of work
@@ -346,3 +510,178 @@ fn make_new_polygon(
 
     Some(endpoints)
 }
+
+// For one road meeting an intersection, the lane type facing a neighbor on one side (the "normal"
+// side matches the `pl_normal` convention above, the "reverse" side matches `pl_reverse`), plus
+// the polyline along the outer curb and the polyline one lane's width in from it.
+fn edge_lane(r: &Road, i: IntersectionID, normal_side: bool) -> (Option<LaneType>, PolyLine, PolyLine) {
+    let fwd_width = LANE_THICKNESS * (r.children_forwards.len() as f64);
+    let back_width = LANE_THICKNESS * (r.children_backwards.len() as f64);
+
+    let (line, width, children) = if r.src_i == i {
+        if normal_side {
+            (r.center_pts.reversed(), back_width, &r.children_backwards)
+        } else {
+            (r.center_pts.reversed(), fwd_width, &r.children_forwards)
+        }
+    } else {
+        if normal_side {
+            (r.center_pts.clone(), fwd_width, &r.children_forwards)
+        } else {
+            (r.center_pts.clone(), back_width, &r.children_backwards)
+        }
+    };
+
+    let lane_type = children.last().map(|(_, lt)| *lt);
+    let inner_width = if width > LANE_THICKNESS {
+        width - LANE_THICKNESS
+    } else {
+        0.0 * si::M
+    };
+
+    if normal_side {
+        let outer = line.shift(width).unwrap();
+        let inner = line.shift(inner_width).unwrap();
+        (lane_type, outer, inner)
+    } else {
+        let outer = line.reversed().shift(width).unwrap().reversed();
+        let inner = line.reversed().shift(inner_width).unwrap().reversed();
+        (lane_type, outer, inner)
+    }
+}
+
+fn is_walkable(lt: Option<LaneType>) -> bool {
+    matches!(lt, Some(LaneType::Sidewalk) | Some(LaneType::Shoulder))
+}
+
+#[cfg(test)]
+mod sidewalk_corner_tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn is_walkable_accepts_only_sidewalk_and_shoulder() {
+        assert!(is_walkable(Some(LaneType::Sidewalk)));
+        assert!(is_walkable(Some(LaneType::Shoulder)));
+        assert!(!is_walkable(Some(LaneType::Driving)));
+        assert!(!is_walkable(Some(LaneType::Biking)));
+        assert!(!is_walkable(None));
+    }
+
+    // Only fills in the fields `sidewalk_corners`/`edge_lane` actually read.
+    fn test_road(
+        id: usize,
+        src: usize,
+        dst: usize,
+        center: PolyLine,
+        children_forwards: Vec<(crate::LaneID, LaneType)>,
+        children_backwards: Vec<(crate::LaneID, LaneType)>,
+    ) -> Road {
+        Road {
+            id: RoadID(id),
+            src_i: IntersectionID(src),
+            dst_i: IntersectionID(dst),
+            center_pts: center,
+            children_forwards,
+            children_backwards,
+            osm_tags: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn sidewalk_corners_fills_in_the_gap_between_two_walkable_facing_edges() {
+        // Two roads meeting at a right angle, both ending at the intersection, each carrying a
+        // sidewalk on the side that faces the other road. `sidewalk_corners` should walk the
+        // angle-sorted incident roads and emit exactly one corner polygon for the one pair of
+        // facing edges that are both walkable -- not for the other, non-walkable pairing between
+        // the same two roads.
+        let i = Intersection {
+            id: IntersectionID(0),
+            roads: vec![RoadID(0), RoadID(1)],
+        };
+        // Road A runs east, ending at the intersection; its *backward* side (the one facing
+        // road B, per `edge_lane`'s src_i/dst_i bookkeeping) is a sidewalk.
+        let road_a = test_road(
+            0,
+            10,
+            0,
+            PolyLine::new(vec![Pt2D::new(-100.0, 0.0), Pt2D::new(0.0, 0.0)]).unwrap(),
+            Vec::new(),
+            vec![(crate::LaneID(0), LaneType::Sidewalk)],
+        );
+        // Road B runs south (y grows downward), ending at the intersection; its *forward* side is
+        // a sidewalk.
+        let road_b = test_road(
+            1,
+            11,
+            0,
+            PolyLine::new(vec![Pt2D::new(0.0, -100.0), Pt2D::new(0.0, 0.0)]).unwrap(),
+            vec![(crate::LaneID(1), LaneType::Sidewalk)],
+            Vec::new(),
+        );
+        let roads = vec![road_a, road_b];
+
+        let corners = sidewalk_corners(&i, &roads);
+        assert_eq!(corners.len(), 1);
+        // Both roads' inner edges land right on the intersection point itself, since each walkable
+        // side is just the one sidewalk lane, so its inner edge sits at zero offset from center.
+        assert!(corners[0]
+            .iter()
+            .any(|pt| *pt == Pt2D::new(0.0, 0.0)));
+    }
+}
+
+/// `initial_intersection_polygon` only fills in the road/vehicle area of an intersection. The
+/// triangular corners between adjacent footways -- where pedestrians actually cut across -- are
+/// left empty. Walk the incident roads in the same sorted-angle order used there, and for each
+/// adjacent pair belonging to different roads where both facing edges are sidewalk or shoulder
+/// lanes, emit a corner polygon: the inner edge endpoint of each road at the intersection, plus
+/// the two outer curb points.
+pub fn sidewalk_corners(i: &Intersection, roads: &Vec<Road>) -> Vec<Vec<Pt2D>> {
+    let mut lines: Vec<(RoadID, Angle)> = i
+        .roads
+        .iter()
+        .map(|id| {
+            let r = &roads[id.0];
+            let line = if r.src_i == i.id {
+                r.center_pts.reversed()
+            } else {
+                r.center_pts.clone()
+            };
+            (*id, line.last_line().angle())
+        })
+        .collect();
+    lines.sort_by_key(|(_, angle)| angle.normalized_degrees() as i64);
+
+    let mut corners = Vec::new();
+    for idx1 in 0..lines.len() as isize {
+        let idx2 = idx1 + 1;
+        let (id1, _) = wraparound_get(&lines, idx1);
+        let (id2, _) = wraparound_get(&lines, idx2);
+        if id1 == id2 {
+            // Only one road incident to this intersection; there's no corner to fill.
+            continue;
+        }
+
+        let (lt1, outer1, inner1) = edge_lane(&roads[id1.0], i.id, false);
+        let (lt2, outer2, inner2) = edge_lane(&roads[id2.0], i.id, true);
+        if !is_walkable(lt1) || !is_walkable(lt2) {
+            continue;
+        }
+
+        let pts = vec![
+            inner1.last_pt(),
+            inner2.last_pt(),
+            outer2.last_pt(),
+            outer1.last_pt(),
+            inner1.last_pt(),
+        ];
+        if let Ok(ring) = Ring::new(pts.clone()) {
+            corners.push(ring.into_points());
+        } else {
+            corners.push(pts);
+        }
+    }
+    corners
+}
@@ -0,0 +1,395 @@
+use std::collections::BTreeSet;
+use std::marker;
+
+use dimensioned::si;
+use geom::PolyLine;
+
+use crate::{Intersection, IntersectionID, Road, RoadID};
+
+// Two one-way roads within this band of each other, over their whole length, are considered the
+// same divided street rather than two genuinely different roads.
+const DUAL_CARRIAGEWAY_BAND: si::Meter<f64> = si::Meter {
+    value_unsafe: 8.0,
+    _marker: marker::PhantomData,
+};
+// A road shorter than this, connecting two otherwise-plain intersections, is probably just a
+// mapping artifact (a stub linking what's really one junction) rather than a real street segment.
+const SHORT_CONNECTOR_LENGTH: si::Meter<f64> = si::Meter {
+    value_unsafe: 15.0,
+    _marker: marker::PhantomData,
+};
+
+/// Run before `initial_intersection_polygon`. The angle-sort there "definitely can break for
+/// merged intersections" -- this pass gets rid of two common causes: divided (dual-carriageway)
+/// streets that were imported as a separate one-way road for each direction, and short connector
+/// roads that just link two closely-spaced intersections that are really one junction.
+pub fn merge_dual_carriageways_and_short_connectors(
+    roads: &mut Vec<Road>,
+    intersections: &mut Vec<Intersection>,
+) {
+    merge_dual_carriageways(roads, intersections);
+    collapse_short_connectors(roads, intersections);
+}
+
+fn street_name(r: &Road) -> Option<&String> {
+    r.osm_tags.get("name")
+}
+
+// Two one-way roads are a dual-carriageway pair if they point opposite directions, share a name,
+// and stay close together (within `DUAL_CARRIAGEWAY_BAND`) over their whole length.
+fn is_dual_carriageway_pair(a: &Road, b: &Road) -> bool {
+    if a.id == b.id || !a.is_oneway() || !b.is_oneway() {
+        return false;
+    }
+    if street_name(a).is_none() || street_name(a) != street_name(b) {
+        return false;
+    }
+    // They have to run the opposite direction of each other to represent the two sides of one
+    // divided street.
+    let b_reversed = b.center_pts.reversed();
+    if a.center_pts.first_pt().dist_to(b_reversed.first_pt()) > DUAL_CARRIAGEWAY_BAND
+        || a.center_pts.last_pt().dist_to(b_reversed.last_pt()) > DUAL_CARRIAGEWAY_BAND
+    {
+        return false;
+    }
+    for pt in a.center_pts.points() {
+        match b_reversed.dist_along_of_point(*pt) {
+            Some((_, dist_off)) if dist_off <= DUAL_CARRIAGEWAY_BAND => {}
+            _ => return false,
+        }
+    }
+    true
+}
+
+// Average two center lines that run the same direction (the caller reverses whichever one
+// doesn't). `.zip()`-ing the two point lists would silently truncate to the shorter one, and the
+// two carriageways almost never have the same node count -- so instead, walk `a`'s own points
+// and sample `b` by distance-along at each one. The first and last points are kept exactly as
+// `a` has them (not averaged), since they're the ones required to still land exactly on the
+// road's `src_i`/`dst_i`.
+fn average_center_lines(a: &PolyLine, b: &PolyLine) -> PolyLine {
+    let a_pts = a.points();
+    let mut pts = Vec::with_capacity(a_pts.len());
+    let mut accumulated = 0.0 * si::M;
+
+    for (idx, pt) in a_pts.iter().enumerate() {
+        if idx > 0 {
+            accumulated += a_pts[idx - 1].dist_to(*pt);
+        }
+        if idx == 0 || idx == a_pts.len() - 1 {
+            pts.push(*pt);
+            continue;
+        }
+        let (other_pt, _) = b.dist_along(accumulated.min(b.length()));
+        pts.push(pt.midpoint(other_pt));
+    }
+    pts.dedup();
+    PolyLine::new(pts).unwrap_or_else(|_| a.clone())
+}
+
+fn merge_dual_carriageways(roads: &mut Vec<Road>, intersections: &mut Vec<Intersection>) {
+    let mut merged: BTreeSet<RoadID> = BTreeSet::new();
+
+    // Snapshot the ids up front. Merging a pair shrinks `roads` in place (the `roads.retain`
+    // below), so driving the outer loop off a stale `0..roads.len()` range -- like this used to --
+    // runs `roads[idx1]` past the new, shorter length as soon as anything merges. Looking up each
+    // id's current position fresh every iteration keeps this safe no matter how much the vector
+    // has shrunk.
+    let all_ids: Vec<RoadID> = roads.iter().map(|r| r.id).collect();
+
+    for id1 in all_ids {
+        if merged.contains(&id1) {
+            continue;
+        }
+        let Some(a) = roads.iter().position(|r| r.id == id1) else {
+            // Shouldn't happen -- roads are only ever removed by merging them into a partner,
+            // which also marks them `merged` -- but don't panic on a stale id if it does.
+            continue;
+        };
+
+        let mut partner = None;
+        for idx2 in 0..roads.len() {
+            let id2 = roads[idx2].id;
+            if id2 == id1 || merged.contains(&id2) {
+                continue;
+            }
+            if is_dual_carriageway_pair(&roads[a], &roads[idx2]) {
+                partner = Some(id2);
+                break;
+            }
+        }
+        let Some(id2) = partner else { continue };
+
+        // Average the two center lines (reversing the second so both run the same direction),
+        // and combine the lane children: road 1's forward lanes stay forward, road 2's (now
+        // reversed) lanes become the backward side of the merged two-way road.
+        let b = roads.iter().position(|r| r.id == id2).unwrap();
+        let (avg_center, back_children) = {
+            let ra = &roads[a];
+            let rb = &roads[b];
+            let avg = average_center_lines(&ra.center_pts, &rb.center_pts.reversed());
+            (avg, rb.children_forwards.clone())
+        };
+
+        roads[a].center_pts = avg_center;
+        roads[a].children_backwards = back_children;
+
+        for i in intersections.iter_mut() {
+            i.roads.retain(|r| *r != id2);
+        }
+        roads.retain(|r| r.id != id2);
+        merged.insert(id2);
+    }
+}
+
+// An intersection that's just two roads passing through, nothing fancier (no turn restrictions,
+// traffic signal, etc. to worry about here) -- the same notion of "plain" that
+// `initial_intersection_polygon` already treats as degenerate.
+fn is_plain_intersection(i: &Intersection) -> bool {
+    i.roads.len() == 2
+}
+
+fn collapse_short_connectors(roads: &mut Vec<Road>, intersections: &mut Vec<Intersection>) {
+    let mut removed_roads: BTreeSet<RoadID> = BTreeSet::new();
+    let mut removed_intersections: BTreeSet<IntersectionID> = BTreeSet::new();
+
+    loop {
+        let Some(connector) = roads.iter().find(|r| {
+            !removed_roads.contains(&r.id)
+                && r.center_pts.length() < SHORT_CONNECTOR_LENGTH
+                && !removed_intersections.contains(&r.src_i)
+                && !removed_intersections.contains(&r.dst_i)
+                && is_plain_intersection(&intersections[r.src_i.0])
+                && is_plain_intersection(&intersections[r.dst_i.0])
+        }) else {
+            break;
+        };
+
+        let (keep, fuse, connector_id) = (connector.src_i, connector.dst_i, connector.id);
+
+        // Re-point every road that used to end at `fuse` so it ends at `keep` instead.
+        for r in roads.iter_mut() {
+            if r.id == connector_id {
+                continue;
+            }
+            if r.src_i == fuse {
+                r.src_i = keep;
+            }
+            if r.dst_i == fuse {
+                r.dst_i = keep;
+            }
+        }
+
+        let fused_roads: Vec<RoadID> = intersections[fuse.0]
+            .roads
+            .iter()
+            .cloned()
+            .filter(|r| *r != connector_id)
+            .collect();
+        for r in &fused_roads {
+            if !intersections[keep.0].roads.contains(r) {
+                intersections[keep.0].roads.push(*r);
+            }
+        }
+        intersections[keep.0].roads.retain(|r| *r != connector_id);
+
+        // `fuse` itself can't be removed from the `intersections` vec without invalidating every
+        // other `IntersectionID` (they're plain indices into it), so instead empty out its road
+        // list. Every road that used to terminate here now points at `keep` instead, so nothing
+        // should ever look this intersection up again -- but leaving stale roads in its list
+        // would make the next geometry pass try anyway and hit a "doesn't have an endpoint here"
+        // panic.
+        intersections[fuse.0].roads.clear();
+
+        roads.retain(|r| r.id != connector_id);
+        removed_roads.insert(connector_id);
+        removed_intersections.insert(fuse);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use geom::Pt2D;
+
+    // Only fills in the fields this file actually reads (id/src_i/dst_i/center_pts/children/tags);
+    // real `Road`s have more, but the merge passes never touch them.
+    fn test_road(id: usize, src: usize, dst: usize, center: PolyLine, name: &str) -> Road {
+        let mut osm_tags = BTreeMap::new();
+        if !name.is_empty() {
+            osm_tags.insert("name".to_string(), name.to_string());
+        }
+        osm_tags.insert("oneway".to_string(), "yes".to_string());
+        Road {
+            id: RoadID(id),
+            src_i: IntersectionID(src),
+            dst_i: IntersectionID(dst),
+            center_pts: center,
+            children_forwards: Vec::new(),
+            children_backwards: Vec::new(),
+            osm_tags,
+        }
+    }
+
+    #[test]
+    fn merge_dual_carriageways_does_not_panic_when_shrinking_mid_iteration() {
+        // Regression test: the outer loop used to capture `0..roads.len()` once and then shrink
+        // `roads` in place (via `retain`) every time a pair merged. As soon as the first pair
+        // merged, later iterations ran past the new, shorter length and panicked indexing
+        // `roads[idx1]`. Three roads -- one mergeable pair, plus one unrelated road that sorts
+        // after them -- is enough to hit the stale range.
+        let a = test_road(
+            0,
+            0,
+            1,
+            PolyLine::new(vec![Pt2D::new(0.0, 0.0), Pt2D::new(100.0, 0.0)]).unwrap(),
+            "Main St",
+        );
+        let b = test_road(
+            1,
+            1,
+            0,
+            PolyLine::new(vec![Pt2D::new(100.0, 4.0), Pt2D::new(0.0, 4.0)]).unwrap(),
+            "Main St",
+        );
+        let c = test_road(
+            2,
+            2,
+            3,
+            PolyLine::new(vec![Pt2D::new(500.0, 500.0), Pt2D::new(600.0, 500.0)]).unwrap(),
+            "Side St",
+        );
+
+        let mut roads = vec![a, b, c];
+        let mut intersections = vec![
+            Intersection {
+                id: IntersectionID(0),
+                roads: vec![RoadID(0), RoadID(1)],
+            },
+            Intersection {
+                id: IntersectionID(1),
+                roads: vec![RoadID(0), RoadID(1)],
+            },
+            Intersection {
+                id: IntersectionID(2),
+                roads: vec![RoadID(2)],
+            },
+            Intersection {
+                id: IntersectionID(3),
+                roads: vec![RoadID(2)],
+            },
+        ];
+
+        merge_dual_carriageways(&mut roads, &mut intersections);
+
+        // The dual-carriageway pair merged down to one road; the unrelated road is untouched.
+        assert_eq!(roads.len(), 2);
+        assert!(roads.iter().any(|r| r.id == RoadID(0)));
+        assert!(roads.iter().any(|r| r.id == RoadID(2)));
+        assert!(!roads.iter().any(|r| r.id == RoadID(1)));
+    }
+
+    #[test]
+    fn collapse_short_connectors_fuses_intersections_and_repoints_roads() {
+        // i0 --d1-- i1 --connector(short)-- i2 --d2-- i3, where i1 and i2 are both plain
+        // (degree-2) intersections joined by a short connector. Collapsing should fuse i2 into
+        // i1 and re-point d2 to start at i1 instead of the now-gone i2.
+        let d1 = test_road(
+            0,
+            0,
+            1,
+            PolyLine::new(vec![Pt2D::new(0.0, 0.0), Pt2D::new(100.0, 0.0)]).unwrap(),
+            "A St",
+        );
+        let connector = test_road(
+            1,
+            1,
+            2,
+            PolyLine::new(vec![Pt2D::new(100.0, 0.0), Pt2D::new(105.0, 0.0)]).unwrap(),
+            "",
+        );
+        let d2 = test_road(
+            2,
+            2,
+            3,
+            PolyLine::new(vec![Pt2D::new(105.0, 0.0), Pt2D::new(200.0, 0.0)]).unwrap(),
+            "B St",
+        );
+
+        let mut roads = vec![d1, connector, d2];
+        let mut intersections = vec![
+            Intersection {
+                id: IntersectionID(0),
+                roads: vec![RoadID(0)],
+            },
+            Intersection {
+                id: IntersectionID(1),
+                roads: vec![RoadID(0), RoadID(1)],
+            },
+            Intersection {
+                id: IntersectionID(2),
+                roads: vec![RoadID(1), RoadID(2)],
+            },
+            Intersection {
+                id: IntersectionID(3),
+                roads: vec![RoadID(2)],
+            },
+        ];
+
+        collapse_short_connectors(&mut roads, &mut intersections);
+
+        // The connector road is gone.
+        assert_eq!(roads.len(), 2);
+        assert!(!roads.iter().any(|r| r.id == RoadID(1)));
+        // `d2` now starts at i1 (kept), not i2 (fused away).
+        let d2 = roads.iter().find(|r| r.id == RoadID(2)).unwrap();
+        assert_eq!(d2.src_i, IntersectionID(1));
+        // The fused intersection's road list is emptied rather than left stale.
+        assert!(intersections[2].roads.is_empty());
+        assert!(intersections[1].roads.contains(&RoadID(2)));
+    }
+
+    #[test]
+    fn average_center_lines_keeps_exact_endpoints() {
+        let a = PolyLine::new(vec![
+            Pt2D::new(0.0, 0.0),
+            Pt2D::new(50.0, 0.0),
+            Pt2D::new(100.0, 0.0),
+        ])
+        .unwrap();
+        // `b` has a different number of points than `a`, like two independently-mapped
+        // carriageways almost always do.
+        let b = PolyLine::new(vec![
+            Pt2D::new(0.0, 4.0),
+            Pt2D::new(30.0, 4.0),
+            Pt2D::new(60.0, 4.0),
+            Pt2D::new(100.0, 4.0),
+        ])
+        .unwrap();
+
+        let avg = average_center_lines(&a, &b);
+        assert_eq!(avg.first_pt(), a.first_pt());
+        assert_eq!(avg.last_pt(), a.last_pt());
+        // An interior point should land roughly halfway between the two lines, not on either one.
+        let mid = avg.dist_along(50.0 * si::M).0;
+        assert!(mid.y() > 0.0 && mid.y() < 4.0);
+    }
+
+    #[test]
+    fn average_center_lines_handles_mismatched_lengths() {
+        // `b` is noticeably shorter than `a`; sampling it past its own length should clamp
+        // instead of panicking.
+        let a = PolyLine::new(vec![
+            Pt2D::new(0.0, 0.0),
+            Pt2D::new(95.0, 0.0),
+            Pt2D::new(100.0, 0.0),
+        ])
+        .unwrap();
+        let b = PolyLine::new(vec![Pt2D::new(0.0, 2.0), Pt2D::new(90.0, 2.0)]).unwrap();
+        let avg = average_center_lines(&a, &b);
+        assert_eq!(avg.first_pt(), a.first_pt());
+        assert_eq!(avg.last_pt(), a.last_pt());
+    }
+}
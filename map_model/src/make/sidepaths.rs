@@ -0,0 +1,393 @@
+use std::collections::BTreeSet;
+use std::marker;
+
+use dimensioned::si;
+use geom::{PolyLine, Pt2D};
+
+use crate::make::curves;
+use crate::raw::{LaneSpec, LaneType, OriginalRoad, RawMap, RawRoad};
+use abstutil::Timer;
+
+// A sidepath further than this from the candidate main road's center line (at any point along the
+// overlap) isn't considered "parallel" anymore.
+const MAX_SIDEPATH_OFFSET: si::Meter<f64> = si::Meter {
+    value_unsafe: 15.0,
+    _marker: marker::PhantomData,
+};
+// The parallel span of the sidepath has to cover at least this fraction of the sidepath's own
+// length, or we're probably looking at two roads that just briefly touch.
+const MIN_OVERLAP_RATIO: f64 = 0.8;
+// If the last-segment directions of the sidepath and the main road differ by more than this,
+// they're not running parallel anymore.
+const MAX_ANGLE_DIFF_DEGREES: f64 = 20.0;
+// Once whatever's left on either end of the parallel span is shorter than this, it's not a real
+// diverging tail worth keeping as its own road -- just noise from where the two lines happen to
+// separate from each other.
+const DIVERGENCE_SLACK: si::Meter<f64> = si::Meter {
+    value_unsafe: 1.0,
+    _marker: marker::PhantomData,
+};
+
+/// Find cycleways/footways that were imported as their own roads, but that actually just
+/// duplicate a sidepath running alongside a main road. Fold each one back into the main road as
+/// an extra lane (with an intervening buffer lane), delete the sidepath's own geometry, and
+/// remove the short connector roads that used to link the sidepath to the main road at each end.
+///
+/// If a sidepath only runs parallel to the main road for part of its length, split it first (via
+/// [`curves::split`]) and only zip in the parallel portion, leaving the end that diverges behind
+/// as its own road. A sidepath that diverges at both ends, or whose parallel span is shorter than
+/// `MIN_OVERLAP_RATIO` of its length, is left as it is.
+pub fn zip_sidepaths(map: &mut RawMap, timer: &mut Timer) {
+    let mut zipped: BTreeSet<OriginalRoad> = BTreeSet::new();
+    let mut removed_connectors: BTreeSet<OriginalRoad> = BTreeSet::new();
+    let mut trimmed = 0;
+
+    timer.start_iter("zip sidepaths", map.roads.len());
+    for id in map.roads.keys().cloned().collect::<Vec<_>>() {
+        timer.next();
+        if zipped.contains(&id) || removed_connectors.contains(&id) {
+            continue;
+        }
+        if !is_sidepath(&map.roads[&id]) {
+            continue;
+        }
+
+        let Some((main_id, side, overlap)) = find_parallel_main_road(map, id) else {
+            continue;
+        };
+
+        let side_pl = PolyLine::must_new(map.roads[&id].center_points.clone());
+        let full_length = side_pl.length();
+
+        // Only `[side_start, side_end]` of the sidepath actually runs parallel to the main road.
+        // Split it off so a sidepath that diverges partway through doesn't drag an unrelated tail
+        // into the overlap calculation (or get deleted along with the part that's really zipped).
+        let (before, rest) = curves::split(&side_pl, overlap.side_start);
+        let (parallel, after) = curves::split(&rest, overlap.side_end - overlap.side_start);
+
+        if parallel.length() < MIN_OVERLAP_RATIO * full_length {
+            continue;
+        }
+
+        let before_len = before.length().min(overlap.side_start);
+        let after_len = full_length - overlap.side_end;
+
+        if before_len > DIVERGENCE_SLACK && after_len > DIVERGENCE_SLACK {
+            // The sidepath diverges from the main road at *both* ends. Keeping one diverging tail
+            // and dropping the other would silently delete real OSM geometry for no better reason
+            // than which end happened to be longer, and keeping both isn't possible without
+            // splitting the main road's single lane-per-whole-road model partway along. Leave the
+            // sidepath alone rather than guess.
+            timer.note(format!(
+                "{} only partially overlaps {} and diverges at both ends; leaving it alone \
+                 instead of arbitrarily keeping one end",
+                id, main_id
+            ));
+            continue;
+        }
+
+        let lane = sidepath_lane_type(&map.roads[&id]);
+        append_zipped_lane(map, main_id, side, lane);
+
+        if before_len <= DIVERGENCE_SLACK && after_len <= DIVERGENCE_SLACK {
+            // Give or take a sliver at either end, the whole sidepath ran parallel -- there's
+            // nothing left of it worth keeping.
+            map.roads.remove(&id);
+            zipped.insert(id);
+
+            // The little stub roads that used to connect the sidepath to the main road at each
+            // end are now dangling off an intersection with nothing else interesting going on;
+            // delete them too.
+            for connector in find_orphaned_connectors(map, id, main_id) {
+                map.roads.remove(&connector);
+                removed_connectors.insert(connector);
+            }
+        } else {
+            // Exactly one end diverges non-trivially; keep it as the sidepath's own road instead
+            // of deleting real, unrelated geometry. The other end is within `DIVERGENCE_SLACK` of
+            // nothing, so there's no real geometry being discarded there, but say so explicitly
+            // rather than silently rewriting the road's center line.
+            let remainder = if before_len > DIVERGENCE_SLACK { before } else { after };
+            timer.note(format!(
+                "{} only partially overlaps {}; keeping its diverging end as its own road",
+                id, main_id
+            ));
+            if let Some(r) = map.roads.get_mut(&id) {
+                r.center_points = remainder.points().clone();
+            }
+            zipped.insert(id);
+            trimmed += 1;
+        }
+    }
+
+    if !zipped.is_empty() {
+        timer.note(format!(
+            "Zipped {} sidepaths into their main roads ({} only partially, trimming the \
+             diverging end), removing {} orphaned connectors",
+            zipped.len(),
+            trimmed,
+            removed_connectors.len()
+        ));
+    }
+}
+
+fn is_sidepath(r: &RawRoad) -> bool {
+    let highway = r.osm_tags.get("highway").map(|x| x.as_str());
+    matches!(highway, Some("cycleway") | Some("footway"))
+}
+
+fn sidepath_lane_type(r: &RawRoad) -> LaneType {
+    if r.osm_tags.get("highway").map(|x| x.as_str()) == Some("cycleway") {
+        LaneType::Biking
+    } else {
+        LaneType::Sidewalk
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Overlap {
+    // How much of the sidepath's own length actually runs parallel to the main road.
+    covered_distance: si::Meter<f64>,
+    // The span, measured along the sidepath itself (not the main road), that's parallel. Used to
+    // split off whichever end diverges.
+    side_start: si::Meter<f64>,
+    side_end: si::Meter<f64>,
+}
+
+// Which side of the main road (relative to its center line, facing the `dst_i` direction) the
+// sidepath runs along.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Side {
+    Left,
+    Right,
+}
+
+// Find a main road running parallel to (and within `MAX_SIDEPATH_OFFSET` of) the sidepath, if one
+// exists, along with which side of it the sidepath is on and how much of the sidepath's own
+// length overlaps.
+fn find_parallel_main_road(
+    map: &RawMap,
+    sidepath: OriginalRoad,
+) -> Option<(OriginalRoad, Side, Overlap)> {
+    let side_pl = PolyLine::must_new(map.roads[&sidepath].center_points.clone());
+
+    let mut best: Option<(OriginalRoad, Side, Overlap)> = None;
+    for (main_id, main) in &map.roads {
+        if *main_id == sidepath || is_sidepath(main) {
+            continue;
+        }
+        let main_pl = PolyLine::must_new(main.center_points.clone());
+        if !roughly_parallel(&side_pl, &main_pl, MAX_ANGLE_DIFF_DEGREES) {
+            continue;
+        }
+
+        let Some((side, overlap)) = measure_overlap(&side_pl, &main_pl) else {
+            continue;
+        };
+        if best
+            .as_ref()
+            .map(|(_, _, o)| overlap.covered_distance > o.covered_distance)
+            .unwrap_or(true)
+        {
+            best = Some((*main_id, side, overlap));
+        }
+    }
+    best
+}
+
+// Walk `side_pl` by its own distance-along, and find the span (if any) that stays within
+// `MAX_SIDEPATH_OFFSET` of `main_pl`. Pulled out of `find_parallel_main_road` so the overlap math
+// can be exercised without needing a whole `RawMap`.
+fn measure_overlap(side_pl: &PolyLine, main_pl: &PolyLine) -> Option<(Side, Overlap)> {
+    let side_pts = side_pl.points();
+    let mut side_dist_along = 0.0 * si::M;
+    let mut first_overlap = None;
+    let mut last_overlap = None;
+    let mut side = None;
+
+    for (idx, pt) in side_pts.iter().enumerate() {
+        if idx > 0 {
+            side_dist_along += side_pts[idx - 1].dist_to(*pt);
+        }
+        let Some((main_dist_along, dist_off)) = main_pl.dist_along_of_point(*pt) else {
+            continue;
+        };
+        if dist_off > MAX_SIDEPATH_OFFSET {
+            continue;
+        }
+        first_overlap.get_or_insert(side_dist_along);
+        last_overlap = Some(side_dist_along);
+        side.get_or_insert(if is_left_of(&main_pl, *pt, main_dist_along) {
+            Side::Left
+        } else {
+            Side::Right
+        });
+    }
+
+    let side = side?;
+    let side_start = first_overlap?;
+    let side_end = last_overlap?;
+    Some((
+        side,
+        Overlap {
+            covered_distance: side_end - side_start,
+            side_start,
+            side_end,
+        },
+    ))
+}
+
+// Which side of `pl`'s direction of travel `pt` falls on: project `pt` onto the line at
+// `dist_along`, then compare the angle from that point to `pt` against the line's direction
+// there.
+fn is_left_of(pl: &PolyLine, pt: Pt2D, dist_along: si::Meter<f64>) -> bool {
+    let (on_line, dir) = pl.dist_along(dist_along);
+    let cross =
+        (dir.normalized_degrees() - on_line.angle_to(pt).normalized_degrees() + 360.0) % 360.0;
+    cross < 180.0
+}
+
+fn roughly_parallel(a: &PolyLine, b: &PolyLine, max_diff_degrees: f64) -> bool {
+    let diff =
+        (a.last_line().angle().normalized_degrees() - b.last_line().angle().normalized_degrees())
+            .abs();
+    let diff = diff.min(360.0 - diff);
+    // A sidepath might be mapped in the opposite direction of the main road; either parallel or
+    // anti-parallel counts.
+    diff < max_diff_degrees || (diff - 180.0).abs() < max_diff_degrees
+}
+
+// Append a new lane (with an intervening buffer lane) to the given side of the main road. The new
+// lane's direction has to match whichever existing lane is actually adjacent to that side: the
+// leftmost lane for the left side, the rightmost for the right -- on a two-way road these usually
+// point opposite ways.
+fn append_zipped_lane(map: &mut RawMap, main: OriginalRoad, side: Side, lane: LaneType) {
+    let r = map.roads.get_mut(&main).unwrap();
+    match side {
+        Side::Left => {
+            let dir = r.lane_specs_ltr[0].dir;
+            let buffer = LaneSpec {
+                lt: LaneType::Buffer,
+                dir,
+                width: crate::NORMAL_LANE_THICKNESS,
+            };
+            let new_lane = LaneSpec {
+                lt: lane,
+                dir,
+                width: crate::NORMAL_LANE_THICKNESS,
+            };
+            r.lane_specs_ltr.insert(0, new_lane);
+            r.lane_specs_ltr.insert(1, buffer);
+        }
+        Side::Right => {
+            let dir = r.lane_specs_ltr.last().unwrap().dir;
+            let buffer = LaneSpec {
+                lt: LaneType::Buffer,
+                dir,
+                width: crate::NORMAL_LANE_THICKNESS,
+            };
+            let new_lane = LaneSpec {
+                lt: lane,
+                dir,
+                width: crate::NORMAL_LANE_THICKNESS,
+            };
+            r.lane_specs_ltr.push(buffer);
+            r.lane_specs_ltr.push(new_lane);
+        }
+    }
+}
+
+// The sidepath used to be connected to the main road by short stub roads at each end (usually a
+// few meters of shared-use path linking the two). Now that the sidepath is gone, those stubs
+// don't lead anywhere interesting; find and remove them. A candidate only counts if its *other*
+// endpoint (the one not touching the removed sidepath) actually lands on `main` -- otherwise it's
+// just some unrelated short road (a driveway, an alley, a second sidepath on a different street)
+// that happens to share an intersection with the one we removed.
+fn find_orphaned_connectors(
+    map: &RawMap,
+    removed_sidepath: OriginalRoad,
+    main: OriginalRoad,
+) -> Vec<OriginalRoad> {
+    let mut result = Vec::new();
+    for (id, r) in &map.roads {
+        if *id == main {
+            continue;
+        }
+        let other_end = if id.i1 == removed_sidepath.i1 || id.i1 == removed_sidepath.i2 {
+            id.i2
+        } else if id.i2 == removed_sidepath.i1 || id.i2 == removed_sidepath.i2 {
+            id.i1
+        } else {
+            continue;
+        };
+        let touches_main = other_end == main.i1 || other_end == main.i2;
+        if touches_main && r.length() < 2.0 * MAX_SIDEPATH_OFFSET {
+            result.push(*id);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measure_overlap_covers_whole_sidepath() {
+        let side = PolyLine::new(vec![Pt2D::new(0.0, 3.0), Pt2D::new(100.0, 3.0)]).unwrap();
+        let main = PolyLine::new(vec![Pt2D::new(0.0, 0.0), Pt2D::new(100.0, 0.0)]).unwrap();
+        let (side_of, overlap) = measure_overlap(&side, &main).unwrap();
+        // y grows downward, so +y is on the right of a line heading +x.
+        assert_eq!(side_of, Side::Right);
+        assert_eq!(overlap.side_start, 0.0 * si::M);
+        assert_eq!(overlap.side_end, 100.0 * si::M);
+        assert_eq!(overlap.covered_distance, 100.0 * si::M);
+    }
+
+    #[test]
+    fn measure_overlap_stops_where_sidepath_diverges() {
+        // Runs alongside `main` for the first 60m, then drifts far away.
+        let side = PolyLine::new(vec![
+            Pt2D::new(0.0, 3.0),
+            Pt2D::new(60.0, 3.0),
+            Pt2D::new(100.0, 200.0),
+        ])
+        .unwrap();
+        let main = PolyLine::new(vec![Pt2D::new(0.0, 0.0), Pt2D::new(100.0, 0.0)]).unwrap();
+        let (_, overlap) = measure_overlap(&side, &main).unwrap();
+        assert_eq!(overlap.side_start, 0.0 * si::M);
+        assert_eq!(overlap.side_end, 60.0 * si::M);
+        assert_eq!(overlap.covered_distance, 60.0 * si::M);
+    }
+
+    #[test]
+    fn measure_overlap_none_when_never_close() {
+        let side = PolyLine::new(vec![Pt2D::new(0.0, 300.0), Pt2D::new(100.0, 300.0)]).unwrap();
+        let main = PolyLine::new(vec![Pt2D::new(0.0, 0.0), Pt2D::new(100.0, 0.0)]).unwrap();
+        assert!(measure_overlap(&side, &main).is_none());
+    }
+
+    #[test]
+    fn is_left_and_right_of_disagree() {
+        let main = PolyLine::new(vec![Pt2D::new(0.0, 0.0), Pt2D::new(100.0, 0.0)]).unwrap();
+        // y grows downward, so -y is on the left of a line heading +x.
+        assert!(is_left_of(&main, Pt2D::new(50.0, -3.0), 50.0 * si::M));
+        assert!(!is_left_of(&main, Pt2D::new(50.0, 3.0), 50.0 * si::M));
+    }
+
+    #[test]
+    fn roughly_parallel_accepts_anti_parallel_sidepath() {
+        // `b` runs the opposite direction of `a`, which is the usual case for an independently
+        // mapped sidepath.
+        let a = PolyLine::new(vec![Pt2D::new(0.0, 3.0), Pt2D::new(100.0, 3.0)]).unwrap();
+        let b = PolyLine::new(vec![Pt2D::new(100.0, 0.0), Pt2D::new(0.0, 0.0)]).unwrap();
+        assert!(roughly_parallel(&a, &b, MAX_ANGLE_DIFF_DEGREES));
+    }
+
+    #[test]
+    fn roughly_parallel_rejects_perpendicular_roads() {
+        let a = PolyLine::new(vec![Pt2D::new(0.0, 0.0), Pt2D::new(100.0, 0.0)]).unwrap();
+        let b = PolyLine::new(vec![Pt2D::new(50.0, -50.0), Pt2D::new(50.0, 50.0)]).unwrap();
+        assert!(!roughly_parallel(&a, &b, MAX_ANGLE_DIFF_DEGREES));
+    }
+}